@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
@@ -22,7 +23,7 @@ use std::sync::{
     Arc, Condvar, Mutex,
 };
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use cadence::{Metric, MetricBuilder, MetricError, MetricResult, MetricSink, StatsdClient};
 use log::{debug, info, warn};
@@ -44,6 +45,8 @@ const STATS_SENDER_QUEUE_SIZE: usize = 4096;
 pub enum StatsOption {
     Tag(&'static str, String),
     Interval(Duration),
+    OrgId(u32),
+    TeamId(u32),
 }
 
 struct Source {
@@ -53,6 +56,8 @@ struct Source {
     tags: Vec<(&'static str, String)>,
     // countdown to next metrics collection
     skip: i64,
+    org_id: u32,
+    team_id: u32,
 }
 
 impl PartialEq for Source {
@@ -76,6 +81,8 @@ pub struct Batch {
     tags: Vec<(&'static str, String)>,
     points: Vec<Counter>,
     timestamp: u32,
+    org_id: u32,
+    team_id: u32,
 }
 
 impl Batch {
@@ -114,10 +121,76 @@ impl Batch {
             tag_values,
             metrics_float_names,
             metrics_float_values,
-            org_id: 0,
-            team_id: 0,
+            org_id: self.org_id,
+            team_id: self.team_id,
         }
     }
+
+    // InfluxDB line protocol: measurement,tagk=tagv,... fieldk=fieldv,... timestamp
+    pub fn to_line_protocol(&self) -> String {
+        let measurement = format!("{}_{}", STATS_PREFIX, self.module).replace("-", "_");
+        let mut line = escape_line_protocol(&measurement, false);
+
+        let mut has_host = false;
+        for (k, v) in self.tags.iter() {
+            if *k == "host" {
+                has_host = true;
+            }
+            // org_id/team_id are reserved: always sourced from self.org_id/
+            // self.team_id below, so a module tag of the same name is
+            // dropped here rather than emitted as a duplicate tag key
+            if *k == "org_id" || *k == "team_id" {
+                continue;
+            }
+            line.push(',');
+            line.push_str(&escape_line_protocol(k, true));
+            line.push('=');
+            line.push_str(&escape_line_protocol(v, true));
+        }
+        if !has_host {
+            line.push_str(",host=");
+            line.push_str(&escape_line_protocol(&self.hostname, true));
+        }
+        line.push_str(&format!(",org_id={},team_id={}", self.org_id, self.team_id));
+
+        line.push(' ');
+        for (i, p) in self.points.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            line.push_str(&escape_line_protocol(p.0, true));
+            line.push('=');
+            match p.2 {
+                CounterValue::Signed(v) => line.push_str(&format!("{}i", v)),
+                CounterValue::Unsigned(v) => line.push_str(&format!("{}i", v)),
+                CounterValue::Float(v) => line.push_str(&v.to_string()),
+            }
+        }
+
+        line.push(' ');
+        line.push_str(&(self.timestamp as u64 * 1_000_000_000).to_string());
+        line
+    }
+}
+
+// escapes commas and spaces, and optionally equals signs, per InfluxDB line
+// protocol rules for measurement/tag key/tag value/field key tokens
+fn escape_line_protocol(s: &str, escape_equals: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ',' | ' ' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '=' if escape_equals => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
 #[derive(Debug)]
@@ -200,6 +273,9 @@ pub struct Collector {
 
     sender: Arc<Sender<ArcBatch>>,
     receiver: Arc<Receiver<ArcBatch>>,
+
+    droplet_sink: Arc<Mutex<Option<Arc<DropletSink>>>>,
+    influxdb_sink: Arc<Mutex<Option<Arc<InfluxdbSink>>>>,
 }
 
 impl Collector {
@@ -230,6 +306,8 @@ impl Collector {
             thread: Mutex::new(None),
             sender: Arc::new(stats_queue_sender),
             receiver: Arc::new(stats_queue_receiver),
+            droplet_sink: Arc::new(Mutex::new(None)),
+            influxdb_sink: Arc::new(Mutex::new(None)),
             ntp_diff,
         };
         s.register_countable(
@@ -254,6 +332,8 @@ impl Collector {
             countable,
             tags: vec![],
             skip: 0,
+            org_id: 0,
+            team_id: 0,
         };
         for tag in module.tags() {
             match tag {
@@ -275,6 +355,8 @@ impl Collector {
                         interval.as_secs() / TICK_CYCLE.as_secs() * TICK_CYCLE.as_secs(),
                     )
                 }
+                StatsOption::OrgId(org_id) => source.org_id = org_id,
+                StatsOption::TeamId(team_id) => source.team_id = team_id,
                 _ => warn!(
                     "ignored tag or invalid interval for module {}",
                     source.module
@@ -339,14 +421,47 @@ impl Collector {
             .store(interval.as_secs(), Ordering::Relaxed);
     }
 
-    fn new_statsd_client<A: ToSocketAddrs + std::fmt::Debug>(
+    pub fn set_remote_sink<A: ToSocketAddrs + std::fmt::Debug>(
+        &self,
+        addr: A,
+    ) -> MetricResult<StatsdClient> {
+        self.set_remote_sink_with_max_payload_size(addr, DROPLET_DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+
+    pub fn set_remote_sink_with_max_payload_size<A: ToSocketAddrs + std::fmt::Debug>(
+        &self,
         addr: A,
+        max_payload_size: usize,
     ) -> MetricResult<StatsdClient> {
+        let (client, sink) = Self::new_statsd_client_with_max_payload_size(addr, max_payload_size)?;
+        *self.droplet_sink.lock().unwrap() = Some(sink);
+        Ok(client)
+    }
+
+    pub fn set_influxdb_sink<S: Into<String>>(&self, endpoint: S) {
+        let sink = Arc::new(Self::new_influxdb_sink(endpoint));
+        *self.influxdb_sink.lock().unwrap() = Some(sink);
+    }
+
+    fn new_statsd_client_with_max_payload_size<A: ToSocketAddrs + std::fmt::Debug>(
+        addr: A,
+        max_payload_size: usize,
+    ) -> MetricResult<(StatsdClient, Arc<DropletSink>)> {
         info!("stats client connect to {:?}", &addr);
 
         let socket = UdpSocket::bind("0.0.0.0:0")?;
-        let sink = DropletSink::from(addr, socket)?;
-        Ok(StatsdClient::from_sink(STATS_PREFIX, sink))
+        let sink = Arc::new(DropletSink::with_max_payload_size(
+            addr,
+            socket,
+            max_payload_size,
+        )?);
+        Ok((StatsdClient::from_sink(STATS_PREFIX, sink.clone()), sink))
+    }
+
+    fn new_influxdb_sink<S: Into<String>>(endpoint: S) -> InfluxdbSink {
+        let endpoint = endpoint.into();
+        info!("stats client connect to {}", &endpoint);
+        InfluxdbSink::new(endpoint)
     }
 
     fn send_metrics<'a, T: Metric + From<String>>(
@@ -389,6 +504,8 @@ impl Collector {
         let min_interval = self.min_interval.clone();
         let sender = self.sender.clone();
         let ntp_diff = self.ntp_diff.clone();
+        let droplet_sink = self.droplet_sink.clone();
+        let influxdb_sink = self.influxdb_sink.clone();
         *self.thread.lock().unwrap() = Some(
             thread::Builder::new()
                 .name("stats-collector".to_owned())
@@ -405,6 +522,10 @@ impl Collector {
                             break;
                         }
 
+                        if let Some(sink) = droplet_sink.lock().unwrap().as_ref() {
+                            sink.flush();
+                        }
+
                         let min_interval_loaded = min_interval.load(Ordering::Relaxed);
                         let now = get_timestamp(ntp_diff.load(Ordering::Relaxed)).as_secs();
                         if now / min_interval_loaded == last_run / min_interval_loaded {
@@ -437,15 +558,24 @@ impl Collector {
                                         tags: source.tags.clone(),
                                         points,
                                         timestamp: now as u32,
+                                        org_id: source.org_id,
+                                        team_id: source.team_id,
                                     });
                                     if let Err(_) = sender.send(ArcBatch(batch.clone())) {
                                         debug!(
                                         "stats to send queue failed because queue have terminated"
                                     );
                                     }
+                                    if let Some(sink) = influxdb_sink.lock().unwrap().as_ref() {
+                                        sink.send(&batch);
+                                    }
                                 }
                             }
                         }
+
+                        if let Some(sink) = influxdb_sink.lock().unwrap().as_ref() {
+                            sink.flush();
+                        }
                     }
                 })
                 .unwrap(),
@@ -453,14 +583,24 @@ impl Collector {
     }
 }
 
+// near a safe UDP MTU (1500 bytes) minus headroom for IP/UDP headers
+const DROPLET_DEFAULT_MAX_PAYLOAD_SIZE: usize = 1400;
+const DROPLET_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
 struct DropletSink {
     addr: SocketAddr,
     socket: UdpSocket,
+    max_payload_size: usize,
     buffer: Mutex<Vec<u8>>,
+    last_flush: Mutex<Instant>,
 }
 
 impl DropletSink {
-    pub fn from<A>(to_addr: A, socket: UdpSocket) -> MetricResult<DropletSink>
+    pub fn with_max_payload_size<A>(
+        to_addr: A,
+        socket: UdpSocket,
+        max_payload_size: usize,
+    ) -> MetricResult<DropletSink>
     where
         A: ToSocketAddrs,
     {
@@ -468,8 +608,10 @@ impl DropletSink {
             Some(addr) => Ok(DropletSink {
                 addr,
                 socket,
+                max_payload_size,
                 // droplet magic
                 buffer: Mutex::new(vec![0, 0, 0, 0, 2]),
+                last_flush: Mutex::new(Instant::now()),
             }),
             None => Err(MetricError::from((
                 cadence::ErrorKind::InvalidInput,
@@ -477,17 +619,138 @@ impl DropletSink {
             ))),
         }
     }
+
+    // sends whatever is currently buffered and resets the buffer back to
+    // just the droplet magic prefix; called every tick so partial buffers
+    // don't linger between flushes triggered by size or interval
+    pub fn flush(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        self.flush_locked(&mut buffer);
+    }
+
+    fn flush_locked(&self, buffer: &mut Vec<u8>) {
+        if buffer.len() > 5 {
+            if let Err(e) = self.socket.send_to(&buffer[..], &self.addr) {
+                warn!("send metrics to droplet sink failed: {}", e);
+            }
+            buffer.truncate(5);
+        }
+        *self.last_flush.lock().unwrap() = Instant::now();
+    }
 }
 
 impl MetricSink for DropletSink {
     fn emit(&self, metric: &str) -> io::Result<usize> {
         let mut buffer = self.buffer.lock().unwrap();
-        buffer.truncate(5);
+
+        let would_overflow = buffer.len() + 1 + metric.len() > self.max_payload_size;
+        let interval_elapsed = self.last_flush.lock().unwrap().elapsed() >= DROPLET_FLUSH_INTERVAL;
+        if buffer.len() > 5 && (would_overflow || interval_elapsed) {
+            self.flush_locked(&mut buffer);
+        }
+
+        if buffer.len() > 5 {
+            buffer.push(b'\n');
+        }
         buffer.extend_from_slice(metric.as_bytes());
-        self.socket.send_to(&buffer[..], &self.addr)
+        Ok(metric.len())
     }
+}
 
-    // TODO: buffer metrics
+// alternative to DropletSink: writes Batch as InfluxDB line protocol over
+// HTTP, so an agent can talk directly to an Influx-compatible endpoint with
+// no collector in between. Each batch is still queued on the protobuf
+// stats::Stats/ArcBatch/Sendable path as well; this sink is an extra output.
+//
+// requires the `reqwest` crate with its "blocking" feature enabled: the
+// blocking client spins up its own internal tokio runtime, so callers of
+// Collector::set_influxdb_sink (which constructs it) must not do so from a
+// thread that is already driving an async reqwest/tokio runtime.
+//
+// the actual HTTP POST runs on a dedicated `influxdb-sender` thread rather
+// than on the `stats-collector` tick thread: flush() only hands the
+// accumulated lines off through a bounded queue, so a slow or unreachable
+// Influx endpoint blocks at most that one thread, not the droplet/protobuf
+// paths that share the tick loop. The queue is bounded (mirroring the
+// STATS_SENDER_QUEUE_SIZE-bounded protobuf sender queue above) so a sender
+// thread stuck waiting out request timeouts during a prolonged outage can't
+// let the backlog of pending flushes grow without bound; once full, flush()
+// evicts the oldest queued payload to make room for the newest one, since
+// fresh percentiles matter more than replaying minutes-old ones.
+const INFLUXDB_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const INFLUXDB_SENDER_QUEUE_SIZE: usize = 64;
+
+struct InfluxdbSink {
+    endpoint: String,
+    buffer: Mutex<String>,
+    queue: Arc<(Mutex<VecDeque<String>>, Condvar)>,
+}
+
+impl InfluxdbSink {
+    fn new(endpoint: String) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(INFLUXDB_REQUEST_TIMEOUT)
+            .build()
+            .expect("failed to build influxdb http client");
+
+        let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+        let sender_queue = queue.clone();
+        let sender_endpoint = endpoint.clone();
+        thread::Builder::new()
+            .name("influxdb-sender".to_owned())
+            .spawn(move || {
+                let (queue, has_item) = &*sender_queue;
+                loop {
+                    let lines = {
+                        let mut queue = queue.lock().unwrap();
+                        while queue.is_empty() {
+                            queue = has_item.wait(queue).unwrap();
+                        }
+                        queue.pop_front().unwrap()
+                    };
+                    if let Err(e) = client.post(&sender_endpoint).body(lines).send() {
+                        warn!(
+                            "send metrics to influxdb endpoint {} failed: {}",
+                            sender_endpoint, e
+                        );
+                    }
+                }
+            })
+            .unwrap();
+
+        Self {
+            endpoint,
+            buffer: Mutex::new(String::new()),
+            queue,
+        }
+    }
+
+    fn send(&self, batch: &Batch) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.push_str(&batch.to_line_protocol());
+        buffer.push('\n');
+    }
+
+    fn flush(&self) {
+        let lines = {
+            let mut buffer = self.buffer.lock().unwrap();
+            if buffer.is_empty() {
+                return;
+            }
+            std::mem::take(&mut *buffer)
+        };
+        let (queue, has_item) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        if queue.len() >= INFLUXDB_SENDER_QUEUE_SIZE {
+            warn!(
+                "influxdb sender queue for endpoint {} is full, dropping oldest pending flush",
+                self.endpoint
+            );
+            queue.pop_front();
+        }
+        queue.push_back(lines);
+        has_item.notify_one();
+    }
 }
 
 #[derive(Default)]
@@ -514,3 +777,384 @@ impl AtomicTimeStats {
             });
     }
 }
+
+// number of significant decimal digits of precision kept across the whole
+// trackable range, i.e. relative error is bounded by roughly 10^-HISTOGRAM_SIGNIFICANT_DIGITS
+const HISTOGRAM_SIGNIFICANT_DIGITS: u32 = 3;
+
+const fn bits_needed(mut value: u64) -> u32 {
+    let mut bits = 0;
+    while value > 0 {
+        value >>= 1;
+        bits += 1;
+    }
+    bits
+}
+
+// smallest k with 2^k >= 10^HISTOGRAM_SIGNIFICANT_DIGITS, i.e. how many linear
+// sub-buckets each power-of-two exponent range is divided into
+const HISTOGRAM_SUB_BUCKET_BITS: u32 = bits_needed(10u64.pow(HISTOGRAM_SIGNIFICANT_DIGITS) - 1);
+const HISTOGRAM_SUB_BUCKET_COUNT: usize = 1 << HISTOGRAM_SUB_BUCKET_BITS;
+const HISTOGRAM_SUB_BUCKET_HALF_COUNT: usize = HISTOGRAM_SUB_BUCKET_COUNT / 2;
+// values above 1 hour are clamped into the top bucket
+const HISTOGRAM_MAX_VALUE_NS: u64 = 3600 * 1_000_000_000;
+
+const fn histogram_bucket_count(max_value: u64) -> usize {
+    let mut buckets = 1;
+    let mut covered = HISTOGRAM_SUB_BUCKET_COUNT as u64;
+    while covered < max_value {
+        covered <<= 1;
+        buckets += 1;
+    }
+    buckets
+}
+const HISTOGRAM_BUCKET_COUNT: usize = histogram_bucket_count(HISTOGRAM_MAX_VALUE_NS);
+// bucket 0 keeps the full sub-bucket range, every bucket after that only
+// needs its upper half since the lower half is already covered, at finer
+// resolution, by the bucket before it
+const HISTOGRAM_SLOT_COUNT: usize =
+    HISTOGRAM_SUB_BUCKET_COUNT + (HISTOGRAM_BUCKET_COUNT - 1) * HISTOGRAM_SUB_BUCKET_HALF_COUNT;
+
+// locates the (exponent bucket, linear offset within it) pair a value falls into
+fn histogram_bucket_and_offset(value_ns: u64) -> (usize, usize) {
+    if value_ns == 0 {
+        return (0, 0);
+    }
+    let highest_bit = 63 - value_ns.leading_zeros();
+    let bucket = highest_bit.saturating_sub(HISTOGRAM_SUB_BUCKET_BITS - 1) as usize;
+    let offset = ((value_ns >> bucket as u32) as usize) & (HISTOGRAM_SUB_BUCKET_COUNT - 1);
+    (bucket, offset)
+}
+
+fn histogram_slot_index(bucket: usize, offset: usize) -> usize {
+    if bucket == 0 {
+        offset
+    } else {
+        HISTOGRAM_SUB_BUCKET_COUNT
+            + (bucket - 1) * HISTOGRAM_SUB_BUCKET_HALF_COUNT
+            + (offset - HISTOGRAM_SUB_BUCKET_HALF_COUNT)
+    }
+}
+
+// representative (midpoint) value, in ns, of the given bucket/offset slot
+fn histogram_value_of(bucket: usize, offset: usize) -> u64 {
+    let resolution = 1u64 << bucket;
+    ((offset as u64) << bucket) + resolution / 2
+}
+
+/// Lock-free HDR-style latency histogram: `update()` is a single `fetch_add`
+/// on a fixed-size array of `AtomicU64` counters, and percentiles are
+/// computed by scanning the buckets on read. See `AtomicTimeStats` for the
+/// simpler count/sum/max alternative.
+pub struct AtomicLatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for AtomicLatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..HISTOGRAM_SLOT_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        }
+    }
+}
+
+impl AtomicLatencyHistogram {
+    pub fn update(&self, duration: Duration) {
+        let value = (duration.as_nanos() as u64).min(HISTOGRAM_MAX_VALUE_NS);
+        let (bucket, offset) = histogram_bucket_and_offset(value);
+        self.buckets[histogram_slot_index(bucket, offset)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // quantile in [0, 1]; returns the representative value of the bucket the
+    // cumulative count crosses the quantile in, or 0 if nothing was recorded
+    pub fn percentile(&self, quantile: f64) -> u64 {
+        let snapshot: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = snapshot.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        // clamp to 1 so quantile == 0.0 (or anything else that rounds down to
+        // 0) still resolves to the first bucket that actually has a sample,
+        // rather than trivially satisfying `cumulative >= target` at index 0
+        let target = ((total as f64 * quantile).ceil() as u64).max(1);
+        let mut cumulative = 0u64;
+        let mut index = 0;
+        for bucket in 0..HISTOGRAM_BUCKET_COUNT {
+            let start_offset = if bucket == 0 {
+                0
+            } else {
+                HISTOGRAM_SUB_BUCKET_HALF_COUNT
+            };
+            for offset in start_offset..HISTOGRAM_SUB_BUCKET_COUNT {
+                cumulative += snapshot[index];
+                if cumulative >= target {
+                    return histogram_value_of(bucket, offset);
+                }
+                index += 1;
+            }
+        }
+        HISTOGRAM_MAX_VALUE_NS
+    }
+
+    pub fn get_counters(&self) -> Vec<Counter> {
+        vec![
+            (
+                "p50_ns",
+                CounterType::Gauged,
+                CounterValue::Unsigned(self.percentile(0.5)),
+            ),
+            (
+                "p90_ns",
+                CounterType::Gauged,
+                CounterValue::Unsigned(self.percentile(0.9)),
+            ),
+            (
+                "p99_ns",
+                CounterType::Gauged,
+                CounterValue::Unsigned(self.percentile(0.99)),
+            ),
+        ]
+    }
+}
+
+impl RefCountable for AtomicLatencyHistogram {
+    fn get_counters(&self) -> Vec<Counter> {
+        AtomicLatencyHistogram::get_counters(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_line_protocol_escapes_commas_and_spaces() {
+        assert_eq!(escape_line_protocol("a,b c", false), "a\\,b\\ c");
+        // equals is only escaped for tag/field keys and values, not measurements
+        assert_eq!(escape_line_protocol("a=b", false), "a=b");
+        assert_eq!(escape_line_protocol("a=b", true), "a\\=b");
+        assert_eq!(escape_line_protocol("plain", true), "plain");
+    }
+
+    fn test_batch(tags: Vec<(&'static str, String)>) -> Batch {
+        Batch {
+            module: "test-module",
+            hostname: "test-host".to_string(),
+            tags,
+            points: vec![
+                ("count", CounterType::Gauged, CounterValue::Unsigned(3)),
+                ("ratio", CounterType::Gauged, CounterValue::Float(0.5)),
+            ],
+            timestamp: 1700000000,
+            org_id: 7,
+            team_id: 9,
+        }
+    }
+
+    #[test]
+    fn to_line_protocol_injects_host_when_missing() {
+        let line = test_batch(vec![("region", "cn".to_string())]).to_line_protocol();
+        let (tags_and_measurement, rest) = line.split_once(' ').unwrap();
+        assert!(tags_and_measurement.starts_with("deepflow_agent_test_module,"));
+        assert!(tags_and_measurement.contains(",region=cn,"));
+        assert!(tags_and_measurement.contains(",host=test-host,"));
+        assert!(tags_and_measurement.ends_with("org_id=7,team_id=9"));
+
+        let (fields, timestamp) = rest.split_once(' ').unwrap();
+        assert!(fields.contains("count=3i"));
+        assert!(fields.contains("ratio=0.5"));
+        assert_eq!(timestamp, "1700000000000000000");
+    }
+
+    #[test]
+    fn to_line_protocol_does_not_duplicate_explicit_host_tag() {
+        let line = test_batch(vec![("host", "explicit-host".to_string())]).to_line_protocol();
+        assert_eq!(line.matches("host=").count(), 1);
+        assert!(line.contains(",host=explicit-host,"));
+    }
+
+    #[test]
+    fn to_line_protocol_escapes_tag_value() {
+        let line = test_batch(vec![("dc", "us, east".to_string())]).to_line_protocol();
+        assert!(line.contains(",dc=us\\,\\ east,"));
+    }
+
+    #[test]
+    fn to_line_protocol_does_not_duplicate_org_or_team_tag() {
+        let line = test_batch(vec![
+            ("org_id", "999".to_string()),
+            ("team_id", "999".to_string()),
+        ])
+        .to_line_protocol();
+        assert_eq!(line.matches("org_id=").count(), 1);
+        assert_eq!(line.matches("team_id=").count(), 1);
+        assert!(line.contains(",org_id=7,team_id=9"));
+    }
+
+    fn droplet_sink_for_test(max_payload_size: usize) -> (DropletSink, UdpSocket) {
+        let recv_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        recv_socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let recv_addr = recv_socket.local_addr().unwrap();
+        let send_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink =
+            DropletSink::with_max_payload_size(recv_addr, send_socket, max_payload_size).unwrap();
+        (sink, recv_socket)
+    }
+
+    #[test]
+    fn droplet_sink_does_not_send_until_overflow_threshold() {
+        let (sink, recv_socket) = droplet_sink_for_test(32);
+        sink.emit("metric.one:1|c").unwrap();
+        // fits comfortably under the 32-byte payload cap, so nothing sent yet
+        let mut buf = [0u8; 64];
+        assert!(recv_socket.recv_from(&mut buf).is_err());
+
+        // this metric would push the buffer past max_payload_size, so the
+        // *previous* contents are flushed first, then this one is buffered
+        sink.emit("metric.two:222222222222|c").unwrap();
+        let (n, _) = recv_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..5], &[0, 0, 0, 0, 2]);
+        assert_eq!(&buf[5..n], b"metric.one:1|c");
+    }
+
+    #[test]
+    fn droplet_sink_flushes_on_interval_even_under_threshold() {
+        let (sink, recv_socket) = droplet_sink_for_test(DROPLET_DEFAULT_MAX_PAYLOAD_SIZE);
+        sink.emit("metric.one:1|c").unwrap();
+        // backdate last_flush instead of sleeping past DROPLET_FLUSH_INTERVAL,
+        // so the test doesn't depend on (and isn't flaky under) wall-clock timing
+        *sink.last_flush.lock().unwrap() = Instant::now() - DROPLET_FLUSH_INTERVAL;
+
+        // the next emit() observes the elapsed interval and flushes the
+        // previously buffered metric before buffering this one
+        sink.emit("metric.two:2|c").unwrap();
+        let mut buf = [0u8; 64];
+        let (n, _) = recv_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[5..n], b"metric.one:1|c");
+    }
+
+    #[test]
+    fn droplet_sink_buffers_single_metric_larger_than_max_payload() {
+        let (sink, recv_socket) = droplet_sink_for_test(8);
+        let oversized = "metric.way.too.long.for.the.payload.cap:1|c";
+        // an empty buffer can't be flushed to make room, so the oversized
+        // metric is still accepted and only sent on the next explicit flush
+        sink.emit(oversized).unwrap();
+        let mut buf = [0u8; 128];
+        assert!(recv_socket.recv_from(&mut buf).is_err());
+
+        sink.flush();
+        let (n, _) = recv_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[5..n], oversized.as_bytes());
+    }
+
+    #[test]
+    fn bucket_and_offset_round_trip_within_error_bound() {
+        // every value in bucket 0 is stored at single-ns resolution, so it
+        // round-trips exactly
+        for value in [0u64, 1, 100, 1023] {
+            let (bucket, offset) = histogram_bucket_and_offset(value);
+            assert_eq!(bucket, 0);
+            assert_eq!(histogram_value_of(bucket, offset), value);
+        }
+
+        // beyond bucket 0 the midpoint is only guaranteed within the bucket's
+        // resolution (2^bucket ns), i.e. within the stated relative error
+        for value in [10_000u64, 1_000_000, 60_000_000_000] {
+            let (bucket, offset) = histogram_bucket_and_offset(value);
+            assert!(bucket > 0);
+            let resolution = 1u64 << bucket;
+            let represented = histogram_value_of(bucket, offset);
+            let error = if represented > value {
+                represented - value
+            } else {
+                value - represented
+            };
+            assert!(
+                error <= resolution,
+                "value {value} represented as {represented} (bucket {bucket}, resolution {resolution})"
+            );
+        }
+    }
+
+    #[test]
+    fn bucket_and_offset_clamp_edges_do_not_panic() {
+        assert_eq!(histogram_bucket_and_offset(0), (0, 0));
+        let (bucket, _) = histogram_bucket_and_offset(HISTOGRAM_MAX_VALUE_NS);
+        assert_eq!(bucket, HISTOGRAM_BUCKET_COUNT - 1);
+    }
+
+    #[test]
+    fn slot_index_is_unique_and_in_bounds() {
+        let mut seen = vec![false; HISTOGRAM_SLOT_COUNT];
+        for bucket in 0..HISTOGRAM_BUCKET_COUNT {
+            let start_offset = if bucket == 0 {
+                0
+            } else {
+                HISTOGRAM_SUB_BUCKET_HALF_COUNT
+            };
+            for offset in start_offset..HISTOGRAM_SUB_BUCKET_COUNT {
+                let index = histogram_slot_index(bucket, offset);
+                assert!(index < HISTOGRAM_SLOT_COUNT);
+                assert!(!seen[index], "duplicate slot index {index}");
+                seen[index] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn percentile_on_empty_histogram_is_zero() {
+        let hist = AtomicLatencyHistogram::default();
+        assert_eq!(hist.percentile(0.5), 0);
+        assert_eq!(hist.percentile(0.99), 0);
+    }
+
+    #[test]
+    fn percentile_on_uniform_distribution_is_within_error_bound() {
+        let hist = AtomicLatencyHistogram::default();
+        for v in 1..=1000u64 {
+            hist.update(Duration::from_nanos(v));
+        }
+        let p50 = hist.percentile(0.5);
+        // true p50 of 1..=1000 is 500; bucket 0 has single-ns resolution so
+        // this should be exact
+        assert_eq!(p50, 500);
+
+        let p99 = hist.percentile(0.99);
+        assert_eq!(p99, 990);
+    }
+
+    #[test]
+    fn percentile_zero_returns_minimum_not_zero() {
+        let hist = AtomicLatencyHistogram::default();
+        for v in [5000u64, 5100, 5200] {
+            hist.update(Duration::from_nanos(v));
+        }
+        // percentile(0.0) must resolve to the smallest recorded sample, not
+        // bucket 0 (which has no samples at all here)
+        assert_eq!(hist.percentile(0.0), hist.percentile(0.01));
+        assert_ne!(hist.percentile(0.0), 0);
+    }
+
+    #[test]
+    fn percentile_clamps_values_above_max_into_top_bucket() {
+        let hist = AtomicLatencyHistogram::default();
+        hist.update(Duration::from_secs(3600 * 10));
+        // does not panic, and lands in the top bucket's resolution band
+        // around HISTOGRAM_MAX_VALUE_NS (the representative midpoint can
+        // round slightly past it)
+        let p99 = hist.percentile(0.99);
+        let top_bucket_resolution = 1u64 << (HISTOGRAM_BUCKET_COUNT - 1);
+        assert!(p99 + top_bucket_resolution >= HISTOGRAM_MAX_VALUE_NS);
+        assert!(p99 <= HISTOGRAM_MAX_VALUE_NS + top_bucket_resolution);
+    }
+}